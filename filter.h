@@ -0,0 +1,71 @@
+#ifndef SUMP_FILTER_H
+#define SUMP_FILTER_H
+
+#include <algorithm>
+#include <cstdint>
+
+// Debounces the pump input. A new level has to be stable for the
+// debounce period of its edge before it's reported. If the pin bounces
+// within that period, the timer restarts so the filter settles on the
+// final level rather than reporting the intermediate ones. An ON level
+// also has to last `min_run`; one that gets past the ON debounce
+// period but not that far is counted as a phantom cycle. It doesn't
+// touch GPIO or sockets so it can be tested on any host.
+
+class Filter {
+    uint64_t const debounce_on;
+    uint64_t const debounce_off;
+    uint64_t const min_run;
+    bool started;
+    bool value;
+    bool pending_value;
+    uint64_t pending_since;
+    uint32_t phantoms;
+
+ public:
+    Filter(uint64_t const settle_on, uint64_t const settle_off,
+	   uint64_t const shortest_run) :
+	debounce_on(settle_on), debounce_off(settle_off),
+	min_run(shortest_run), started(false), value(false),
+	pending_value(false), pending_since(0), phantoms(0)
+    {
+    }
+
+    // Takes a sample of the pin and returns the debounced level. The
+    // first sample is taken as-is so the initial report reflects the
+    // pin rather than the default state.
+
+    bool update(bool const raw, uint64_t const stamp)
+    {
+	if (!started) {
+	    started = true;
+	    value = pending_value = raw;
+	    pending_since = stamp;
+	    return value;
+	}
+	if (raw != pending_value) {
+	    if (!raw && !value && stamp - pending_since >= debounce_on &&
+		phantoms < UINT32_MAX)
+		++phantoms;
+	    pending_value = raw;
+	    pending_since = stamp;
+	}
+
+	uint64_t const debounce =
+	    raw ? std::max(debounce_on, min_run) : debounce_off;
+
+	if (stamp - pending_since >= debounce)
+	    value = raw;
+	return value;
+    }
+
+    // The time the pin's current level was first seen, which is when
+    // a transition really happened rather than when it settled.
+
+    uint64_t since() const { return pending_since; }
+
+    uint32_t phantom_count() const { return phantoms; }
+    void clear_phantoms() { phantoms = 0; }
+};
+
+#endif
@@ -11,15 +11,220 @@
 #include <sys/mman.h>
 #include <poll.h>
 #include <netinet/in.h>
+#include <netinet/tcp.h>
+#include <netdb.h>
 #include <syslog.h>
+#include <algorithm>
+#include <cerrno>
+#include <cstdint>
 #include <cstring>
 #include <stdexcept>
+#include "filter.h"
+#include "report.h"
+
+// How often (in milliseconds) the current state is re-sent to a
+// connected client when the pump hasn't changed. The pump can sit
+// idle for hours, so this lets the client tell a quiet sump from a
+// dead connection.
+
+static uint64_t const keepalive_interval = 30000;
+
+// TCP keepalive settings (in seconds) for client connections. The
+// application keepalive above fires first: it sends data every 30
+// seconds, so the connection is never idle long enough for TCP
+// keepalive probes to start, and a dead peer is found when that data
+// goes unacknowledged. The probes only matter if application
+// keepalives stop (e.g. `keepalive_interval` is raised above
+// `tcp_keep_idle`); then a dead peer is dropped after
+// tcp_keep_idle + tcp_keep_count * tcp_keep_interval seconds. Raise
+// these on high-latency networks.
+
+static int const tcp_keep_idle = 60;
+static int const tcp_keep_interval = 10;
+static int const tcp_keep_count = 3;
+
+// If the pump runs continuously for longer than this (in
+// milliseconds), a warning is logged. A healthy pump empties the pit
+// in well under this time so a long run usually means the pump can't
+// keep up or the switch is stuck.
+
+static uint64_t const max_runtime = 5 * 60 * 1000;
+
+// A pump that has been on for this long (in milliseconds) is assumed
+// to be stuck -- a welded relay or a float that won't drop. The
+// condition stays latched until the pump is seen turning off.
+
+static uint64_t const stuck_runtime = 30 * 60 * 1000;
+
+// During a wet season, a pump that hasn't run for a long time may
+// mean the switch failed open. If DRY_HOURS is defined at build time,
+// a notice is logged when the pump has been off for that many hours.
+// In dry seasons pumps can sit idle for weeks, so this is off by
+// default.
+
+#if !defined(DRY_HOURS)
+#define DRY_HOURS 0
+#endif
+
+static uint64_t const dry_interval = DRY_HOURS * 60ULL * 60 * 1000;
+
+// The current switch's relay can chatter as the motor starts and
+// stops. A new pin level has to be stable for this long (in
+// milliseconds) before it's reported. The ON and OFF edges can be
+// given different periods when one of them is noisier by defining
+// DEBOUNCE_ON and DEBOUNCE_OFF at build time.
+
+#if !defined(DEBOUNCE_ON)
+#define DEBOUNCE_ON 100
+#endif
+
+#if !defined(DEBOUNCE_OFF)
+#define DEBOUNCE_OFF 100
+#endif
+
+static uint64_t const default_debounce_on = DEBOUNCE_ON;
+static uint64_t const default_debounce_off = DEBOUNCE_OFF;
+
+// Water sloshing against the float can run the pump for a moment.
+// ON periods shorter than this (in milliseconds) aren't counted as
+// cycles or reported; they're tallied as phantom cycles instead.
+
+static uint64_t const min_runtime = 2000;
+
+// A float oscillating at the water line can make the pump cycle
+// rapidly. If there are more than `flap_limit` transitions within
+// `flap_window` milliseconds, a warning is logged and changes are no
+// longer pushed to clients until a window passes without flapping.
+// Clients still see the newest state through keepalives (so at most
+// one report per `keepalive_interval`), the 's' command and UDP
+// queries.
+
+static unsigned const flap_limit = 10;
+static uint64_t const flap_window = 60 * 1000;
+
+// The most recent transitions are kept so they can be replayed to a
+// client when it connects. Entries older than `history_age` (in
+// milliseconds) aren't replayed.
+
+static size_t const history_size = 32;
+static uint64_t const history_age = 60 * 60 * 1000;
+
+// A client can send a `TEST` line to raise a test alarm through the
+// same syslog path real alarms use. At most one is raised per
+// `test_alarm_interval` milliseconds, however many clients ask or
+// reconnect, so the alerting chain downstream of syslog can't be
+// flooded.
 
-static bool done = false;
+static uint64_t const test_alarm_interval = 30000;
+
+// Number of clients that can be connected at once. When another
+// binary client connects, the oldest text client is dropped to make
+// room, or the oldest client if there are no text clients. Text
+// clients are only for debugging, so a new one is turned away rather
+// than disconnecting anyone.
+
+static size_t const max_clients = 3;
+
+// Number of client connect/disconnect events kept for troubleshooting
+// a client that keeps dropping its connection.
+
+static size_t const audit_size = 16;
+
+// Room for a printed peer address: "[" IPv6 address "]:" port.
+
+static size_t const addr_size = INET6_ADDRSTRLEN + 8;
+
+// Clients on `service_port` receive the binary reports. Clients on
+// `text_port` receive the same reports as lines of text, which is
+// handy when watching the pump with `nc` or a quick script. Both can
+// be changed at build time by defining SERVICE_PORT and TEXT_PORT.
+
+#if !defined(SERVICE_PORT)
+#define SERVICE_PORT 10000
+#endif
+
+#if !defined(TEXT_PORT)
+#define TEXT_PORT (SERVICE_PORT + 1)
+#endif
+
+static_assert(SERVICE_PORT > 0 && SERVICE_PORT <= 65535,
+	      "SERVICE_PORT must be a valid TCP port");
+static_assert(TEXT_PORT > 0 && TEXT_PORT <= 65535,
+	      "TEXT_PORT must be a valid TCP port");
+static_assert(SERVICE_PORT != TEXT_PORT,
+	      "SERVICE_PORT and TEXT_PORT must be different");
+
+static uint16_t const service_port = SERVICE_PORT;
+static uint16_t const text_port = TEXT_PORT;
+
+// Tools that don't want to hold a connection open can send a
+// datagram to this UDP port and get the current state back as a
+// single binary report. It can be changed at build time by defining
+// QUERY_PORT.
+
+#if !defined(QUERY_PORT)
+#define QUERY_PORT SERVICE_PORT
+#endif
+
+static_assert(QUERY_PORT > 0 && QUERY_PORT <= 65535,
+	      "QUERY_PORT must be a valid UDP port");
+
+static uint16_t const query_port = QUERY_PORT;
+
+// The source address of a datagram can't be trusted, so queries
+// shorter than a report are ignored; otherwise the driver would send
+// a forged victim more bytes than the attacker spent. At most
+// `max_queries` are answered per sample so a flood of datagrams
+// can't hold up the sampling loop; the rest wait for the next one.
+
+static size_t const max_queries = 4;
+
+// GPIO pin assignments. The pump input needs a pull-up configured
+// (see README.md); the LEDs are wired active-low. Boards wired
+// differently can override these at build time.
+
+#if !defined(PUMP_PIN)
+#define PUMP_PIN 4
+#endif
+
+#if !defined(ACTIVITY_PIN)
+#define ACTIVITY_PIN 17
+#endif
+
+#if !defined(CLIENT_PIN)
+#define CLIENT_PIN 18
+#endif
+
+static int const pump_pin = PUMP_PIN;
+static int const activity_pin = ACTIVITY_PIN;
+static int const client_pin = CLIENT_PIN;
+
+// The current switch pulls the input low when the pump runs. Builds
+// for inputs wired the other way define PUMP_ACTIVE_HIGH.
+
+#if defined(PUMP_ACTIVE_HIGH)
+static bool const pump_active_low = false;
+#else
+static bool const pump_active_low = true;
+#endif
+
+static volatile sig_atomic_t done = 0;
+static volatile sig_atomic_t report_stats = 0;
+static volatile sig_atomic_t clear_stats = 0;
 
 static void quit(int)
 {
-    done = true;
+    done = 1;
+}
+
+static void request_stats(int)
+{
+    report_stats = 1;
+}
+
+static void request_clear(int)
+{
+    clear_stats = 1;
 }
 
 static uint64_t get_time()
@@ -55,39 +260,138 @@ static void sleep_until(uint64_t const timebase)
 }
 
 class State {
+    uint64_t const started;
+
+    struct Event {
+	uint64_t stamp;
+	bool value;
+    };
+
     uint64_t last_stamp;
     bool last_value;
+    uint64_t next_keepalive;
+    bool ran_too_long;
+    bool stuck;
+    bool idle_too_long;
+    uint32_t cycles;
+
+    // The first state comes from the startup sample rather than an
+    // edge, so the interval that follows it isn't a complete run or
+    // idle period and is left out of these.
+
+    bool seen_edge;
+    uint64_t longest_run;
+    uint64_t total_runtime;
+    uint64_t shortest_idle;
+
+    uint64_t flap_start;
+    unsigned flap_count;
+    bool flapping;
+
+    Event history[history_size];
+    size_t history_len;
+    size_t history_next;
+
+    Filter input;
+
+    struct Client {
+	int fd;
+
+	// Text clients get a few extra lines (the greeting, the
+	// connection log and BYE). The binary protocol has no packet
+	// for these, so binary clients get nothing.
+
+	bool text;
+	char addr[addr_size];
+
+	// The line the client is in the middle of sending. Only
+	// enough is kept to recognize `TEST`; `line_len` keeps
+	// counting past that so a longer line doesn't match.
+
+	char line[sizeof("TEST\r") - 1];
+	size_t line_len;
+    };
+
+    struct Audit {
+	uint64_t stamp;
+	bool connected;
+	char addr[addr_size];
+    };
 
     int const s_listen;
-    int s_client;
+    int const s_text_listen;
+    int const s_query;
+    Client client[max_clients];
+    size_t n_clients;
+    uint64_t next_test_alarm;
+
+    Audit audit[audit_size];
+    size_t audit_len;
+    size_t audit_next;
     int const h_gpio;
 
-    static int create_listener()
+    // Opens a non-blocking socket of the given type bound to `port`
+    // on every address. An IPv6 socket is used when the host supports
+    // it, with IPV6_V6ONLY turned off so IPv4 peers can reach it too
+    // (as IPv4-mapped addresses). If either isn't possible, an IPv4
+    // socket is used so existing IPv4 clients keep working.
+
+    static int create_socket(int const type, uint16_t const port)
     {
-	int const s = socket(PF_INET, SOCK_STREAM, 0);
+	int const s = socket(PF_INET6, type, 0);
 
+	if (s == -1 && (errno == EAFNOSUPPORT || errno == EPROTONOSUPPORT))
+	    return create_ipv4_socket(type, port);
 	if (s == -1)
-	    throw std::runtime_error("couldn't open listener socket");
+	    throw std::runtime_error("couldn't open socket");
+
+	try {
+	    sockaddr_in6 addr;
+	    int const off = 0;
+
+	    std::memset(&addr, 0, sizeof(addr));
+	    addr.sin6_len = sizeof(addr);
+	    addr.sin6_family = AF_INET6;
+	    addr.sin6_port = htons(port);
+	    addr.sin6_addr = in6addr_any;
+
+	    if (setsockopt(s, IPPROTO_IPV6, IPV6_V6ONLY, &off,
+			   sizeof(off)) == -1) {
+		syslog(LOG_WARNING, "couldn't clear IPV6_V6ONLY ... "
+		       "using IPv4 only");
+		close(s);
+		return create_ipv4_socket(type, port);
+	    }
+	    if (bind(s, reinterpret_cast<sockaddr*>(&addr), sizeof(addr)) == -1)
+		throw std::runtime_error("couldn't bind socket");
+	    set_nonblocking(s);
+	    return s;
+	}
+	catch (...) {
+	    close(s);
+	    throw;
+	}
+    }
+
+    static int create_ipv4_socket(int const type, uint16_t const port)
+    {
+	int const s = socket(PF_INET, type, 0);
+
+	if (s == -1)
+	    throw std::runtime_error("couldn't open socket");
 
 	try {
 	    sockaddr_in addr;
 
+	    std::memset(&addr, 0, sizeof(addr));
 	    addr.sin_len = sizeof(addr);
 	    addr.sin_family = AF_INET;
-	    addr.sin_port = htons(10000);
+	    addr.sin_port = htons(port);
 	    addr.sin_addr.s_addr = htonl(INADDR_ANY);
 
 	    if (bind(s, reinterpret_cast<sockaddr*>(&addr), sizeof(addr)) == -1)
-		throw std::runtime_error("couldn't bind listener socket");
-
-	    int const flags = fcntl(s, F_GETFL);
-
-	    if (flags == -1)
-		throw std::runtime_error("couldn't get flags on socket");
-	    if (fcntl(s, F_SETFL, flags | O_NONBLOCK) == -1)
-		throw std::runtime_error("couldn't set flags on socket");
-	    if (listen(s, 1) == -1)
-		throw std::runtime_error("couldn't listen on socket");
+		throw std::runtime_error("couldn't bind socket");
+	    set_nonblocking(s);
 	    return s;
 	}
 	catch (...) {
@@ -96,6 +400,32 @@ class State {
 	}
     }
 
+    static void set_nonblocking(int const s)
+    {
+	int const flags = fcntl(s, F_GETFL);
+
+	if (flags == -1)
+	    throw std::runtime_error("couldn't get flags on socket");
+	if (fcntl(s, F_SETFL, flags | O_NONBLOCK) == -1)
+	    throw std::runtime_error("couldn't set flags on socket");
+    }
+
+    static int create_listener(uint16_t const port)
+    {
+	int const s = create_socket(SOCK_STREAM, port);
+
+	if (listen(s, max_clients) == -1) {
+	    close(s);
+	    throw std::runtime_error("couldn't listen on socket");
+	}
+	return s;
+    }
+
+    static int create_query_socket(uint16_t const port)
+    {
+	return create_socket(SOCK_DGRAM, port);
+    }
+
     static int open_gpio()
     {
 	static char const dev_name[] = "/dev/gpio0";
@@ -111,7 +441,7 @@ class State {
 	struct gpio_req req;
 
 	std::memset(&req, 0, sizeof(req));
-	req.gp_pin = 18;
+	req.gp_pin = client_pin;
 	req.gp_value = v ? 0 : 1;
 	ioctl(h_gpio, GPIOWRITE, &req);
     }
@@ -121,7 +451,7 @@ class State {
 	struct gpio_req req;
 
 	std::memset(&req, 0, sizeof(req));
-	req.gp_pin = 17;
+	req.gp_pin = activity_pin;
 	req.gp_value = v ? 0 : 1;
 	ioctl(h_gpio, GPIOWRITE, &req);
     }
@@ -131,58 +461,374 @@ class State {
 	struct gpio_req req;
 
 	std::memset(&req, 0, sizeof(req));
-	req.gp_pin = 4;
+	req.gp_pin = pump_pin;
 
 	if (ioctl(h_gpio, GPIOREAD, &req) == -1)
 	    throw(std::runtime_error("can't read 'sump' pin state"));
 
-	return !req.gp_value;
+	return pump_active_low ? !req.gp_value : req.gp_value;
     }
 
-    void send_state()
+    // Writes the whole buffer to the client, picking up where a
+    // partial write left off. Returns false if the connection failed
+    // or the client isn't reading its data.
+
+    static bool send_all(int const s, uint8_t const* buf, size_t len)
     {
-	if (s_client != -1 && last_stamp != 0) {
-	    uint8_t buf[12];
+	while (len > 0) {
+	    ssize_t const n = send(s, buf, len, MSG_NOSIGNAL);
 
-	    buf[0] = last_stamp >> 56;
-	    buf[1] = last_stamp >> 48;
-	    buf[2] = last_stamp >> 40;
-	    buf[3] = last_stamp >> 32;
-	    buf[4] = last_stamp >> 24;
-	    buf[5] = last_stamp >> 16;
-	    buf[6] = last_stamp >> 8;
-	    buf[7] = last_stamp;
+	    if (n == -1) {
+		if (errno == EINTR)
+		    continue;
+		return false;
+	    }
+	    buf += n;
+	    len -= n;
+	}
+	return true;
+    }
 
-	    buf[8] = buf[9] = buf[10] = 0;
-	    buf[11] = last_value;
+    void record_audit(uint64_t const stamp, bool const connected,
+		      char const* addr)
+    {
+	audit[audit_next].stamp = stamp;
+	audit[audit_next].connected = connected;
+	std::strncpy(audit[audit_next].addr, addr,
+		     sizeof(audit[audit_next].addr) - 1);
+	audit[audit_next].addr[sizeof(audit[audit_next].addr) - 1] = '\0';
+	audit_next = (audit_next + 1) % audit_size;
+	if (audit_len < audit_size)
+	    ++audit_len;
+    }
+
+    Audit const& audit_entry(size_t const ii) const
+    {
+	return audit[(audit_next + audit_size - audit_len + ii) % audit_size];
+    }
 
-	    if (send(s_client, buf, sizeof(buf), MSG_NOSIGNAL) != sizeof(buf)) {
-		syslog(LOG_WARNING, "couldn't send to client ... "
-		       "closing connection");
-		set_client(false);
-		close(s_client);
-		s_client = -1;
+    // Sends the connection audit log, oldest first, to a text client.
+
+    bool send_audit(size_t const idx, uint64_t const now)
+    {
+	if (client[idx].text)
+	    for (size_t ii = 0; ii < audit_len; ++ii) {
+		Audit const& e = audit_entry(ii);
+		char buf[sizeof("DISCONNECT ") + 20 + 1 + addr_size + 1];
+		int const n = snprintf(buf, sizeof(buf), "%s %llu %s\n",
+				       e.connected ? "CONNECT" : "DISCONNECT",
+				       static_cast<unsigned long long>(e.stamp),
+				       e.addr);
+
+		if (!send_line(idx, buf, sizeof(buf), n, now))
+		    return false;
 	    }
+	return true;
+    }
+
+    // Tells a text client the address we see it connecting from, which
+    // helps track down NAT and routing surprises.
+
+    bool send_greeting(size_t const idx, uint64_t const now)
+    {
+	if (!client[idx].text)
+	    return true;
+
+	char buf[sizeof("CLIENT ") + addr_size + 1];
+	int const n = snprintf(buf, sizeof(buf), "CLIENT %s\n",
+			       client[idx].addr);
+
+	return send_line(idx, buf, sizeof(buf), n, now);
+    }
+
+    // Sends a line built by snprintf. `n` is snprintf's return value,
+    // which can exceed the buffer if the line was truncated, so it's
+    // clamped to what's actually in the buffer.
+
+    bool send_line(size_t const idx, char const* buf, size_t const size,
+		   int const n, uint64_t const now)
+    {
+	size_t const len = n < 0 ? 0 : std::min(size_t(n), size - 1);
+
+	return send_buffer(idx, reinterpret_cast<uint8_t const*>(buf), len,
+			   now);
+    }
+
+    // Tells a text client we're closing its connection, so it can
+    // tell being evicted from a network failure. It's best-effort;
+    // the client is dropped either way.
+
+    void say_goodbye(size_t const idx)
+    {
+	if (client[idx].text)
+	    send_bye(client[idx].fd);
+    }
+
+    static void send_bye(int const s)
+    {
+	static char const bye[] = "BYE\n";
+
+	send_all(s, reinterpret_cast<uint8_t const*>(bye), sizeof(bye) - 1);
+    }
+
+    // Frees a client slot for a new connection, following the rules
+    // described at `max_clients`. Returns false if a new text client
+    // has to be turned away.
+
+    bool make_room(bool const text, uint64_t const now)
+    {
+	if (n_clients < max_clients)
+	    return true;
+	if (text)
+	    return false;
+
+	size_t idx = 0;
+
+	while (idx < n_clients && !client[idx].text)
+	    ++idx;
+	if (idx == n_clients)
+	    idx = 0;
+
+	syslog(LOG_INFO, "too many clients ... dropping %s",
+	       client[idx].addr);
+	say_goodbye(idx);
+	drop_client(idx, now);
+	return true;
+    }
+
+    // Closes the connection to a client. The remaining clients are
+    // kept in the order they connected.
+
+    void drop_client(size_t const idx, uint64_t const now)
+    {
+	syslog(LOG_INFO, "client disconnected: %s", client[idx].addr);
+	record_audit(now, false, client[idx].addr);
+	close(client[idx].fd);
+	for (size_t ii = idx + 1; ii < n_clients; ++ii)
+	    client[ii - 1] = client[ii];
+	set_client(--n_clients > 0);
+    }
+
+    // Sends a report to a client. Returns false if the client had to
+    // be dropped.
+
+    bool send_report(size_t const idx, uint64_t const stamp,
+		     bool const value, uint64_t const now)
+    {
+	if (client[idx].text)
+	    return send_text_report(idx, stamp, value, now);
+
+	uint8_t buf[report_size];
+
+	encode_report(buf, stamp, value);
+	return send_buffer(idx, buf, sizeof(buf), now);
+    }
+
+    // Sends a report as a line of text, e.g. "PUMP ON 123456".
+
+    bool send_text_report(size_t const idx, uint64_t const stamp,
+			  bool const value, uint64_t const now)
+    {
+	char buf[40];
+	int const n = snprintf(buf, sizeof(buf), "PUMP %s %llu\n",
+			       value ? "ON" : "OFF",
+			       static_cast<unsigned long long>(stamp));
+
+	return send_buffer(idx, reinterpret_cast<uint8_t const*>(buf), n, now);
+    }
+
+    bool send_buffer(size_t const idx, uint8_t const* buf, size_t const len,
+		     uint64_t const now)
+    {
+	if (!send_all(client[idx].fd, buf, len)) {
+	    syslog(LOG_WARNING, "couldn't send to client (%m) ... "
+		   "closing connection");
+	    drop_client(idx, now);
+	    return false;
 	}
+	return true;
     }
 
-    void print_addr(char buf[22], uint32_t const addr, uint16_t const port)
+    void send_state(uint64_t const now)
     {
-	snprintf(buf, 22, "%d.%d.%d.%d:%d", uint8_t(addr >> 24),
-		 uint8_t(addr >> 16), uint8_t(addr >> 8), uint8_t(addr), port);
+	if (last_stamp != 0) {
+	    next_keepalive = now + keepalive_interval;
+
+	    // Walk the list backwards so dropping a client doesn't
+	    // shift an unvisited one into the current slot.
+
+	    for (size_t ii = n_clients; ii > 0; --ii)
+		send_report(ii - 1, last_stamp, last_value, now);
+	}
     }
 
-    void check_for_clients()
+    // Starts a new flapping window when the current one has expired.
+    // Returns true if reports were being held and the window that
+    // just ended was quiet, which means reporting should resume.
+
+    bool end_flap_window(uint64_t const stamp)
     {
-	sockaddr_in addr;
-	socklen_t len;
-	int const s = accept(s_listen, reinterpret_cast<sockaddr*>(&addr), &len);
+	bool resumed = false;
+
+	if (stamp - flap_start > flap_window) {
+	    if (flapping && flap_count <= flap_limit) {
+		syslog(LOG_NOTICE, "pump input has settled ... "
+		       "resuming reports");
+		flapping = false;
+		resumed = true;
+	    }
+	    flap_start = stamp;
+	    flap_count = 0;
+	}
+	return resumed;
+    }
 
-	if (s != -1) {
-	    set_client(true);
+    void record_event(uint64_t const stamp, bool const value)
+    {
+	history[history_next].stamp = stamp;
+	history[history_next].value = value;
+	history_next = (history_next + 1) % history_size;
+	if (history_len < history_size)
+	    ++history_len;
+    }
+
+    // Sends the recent transitions, oldest first, followed by the
+    // current state, so a client that was disconnected for a while
+    // can fill in what it missed. The newest history entry is the
+    // current state, so the loop skips it.
+
+    bool replay_history(size_t const idx, uint64_t const now)
+    {
+	for (size_t ii = history_len; ii > 1; --ii) {
+	    Event const& e =
+		history[(history_next + history_size - ii) % history_size];
+
+	    if (now - e.stamp <= history_age &&
+		!send_report(idx, e.stamp, e.value, now))
+		return false;
+	}
+	return last_stamp == 0 ||
+	    send_report(idx, last_stamp, last_value, now);
+    }
+
+    // Returns true if the line a client just finished (not counting
+    // the newline) is exactly `TEST`, with an optional carriage return.
+
+    static bool is_test_line(Client const& c)
+    {
+	size_t len = c.line_len;
+
+	if (len > sizeof(c.line))
+	    return false;
+	if (len > 0 && c.line[len - 1] == '\r')
+	    --len;
+	return len == 4 && std::memcmp(c.line, "TEST", 4) == 0;
+    }
+
+    // Raises a test alarm, so the alerting chain downstream of syslog
+    // can be checked without waiting for a flood. The pump state and
+    // statistics aren't touched.
+
+    void test_alarm(size_t const idx, uint64_t const now)
+    {
+	if (now >= next_test_alarm) {
+	    syslog(LOG_WARNING, "TEST alarm requested by %s",
+		   client[idx].addr);
+	    next_test_alarm = now + test_alarm_interval;
+	}
+    }
+
+    // Handles the single-byte commands a client may send, and the
+    // `TEST` line. Unknown bytes are ignored. Returns false if the
+    // client was dropped.
+
+    bool handle_commands(size_t const idx, char const* buf, size_t const len,
+			 uint64_t const now)
+    {
+	for (size_t ii = 0; ii < len; ++ii) {
+	    Client& c = client[idx];
+
+	    if (buf[ii] != '\n') {
+		if (c.line_len < sizeof(c.line))
+		    c.line[c.line_len] = buf[ii];
+		++c.line_len;
+	    }
 
-	    if (s_client != -1)
-		close(s_client);
+	    switch (buf[ii]) {
+		case 's':
+		    if (last_stamp != 0 &&
+			!send_report(idx, last_stamp, last_value, now))
+			return false;
+		    break;
+
+		case 'h':
+		    if (!replay_history(idx, now))
+			return false;
+		    break;
+
+		case 'a':
+		    if (!send_audit(idx, now))
+			return false;
+		    break;
+
+		case '\n':
+		    if (is_test_line(c))
+			test_alarm(idx, now);
+		    c.line_len = 0;
+		    break;
+
+		default:
+		    break;
+	    }
+	}
+	return true;
+    }
+
+    // Formats a peer's address as "a.b.c.d:port" or "[v6addr]:port".
+    // IPv4 peers of an IPv6 socket show up as IPv4-mapped addresses,
+    // which are printed in plain IPv4 form.
+
+    static void print_addr(char buf[addr_size], sockaddr const* const addr,
+			   socklen_t const len)
+    {
+	char host[INET6_ADDRSTRLEN];
+	char serv[8];
+
+	if (getnameinfo(addr, len, host, sizeof(host), serv, sizeof(serv),
+			NI_NUMERICHOST | NI_NUMERICSERV) != 0) {
+	    snprintf(buf, addr_size, "(unknown)");
+	    return;
+	}
+
+	static char const mapped[] = "::ffff:";
+	char const* h = host;
+
+	if (std::strncmp(h, mapped, sizeof(mapped) - 1) == 0 &&
+	    std::strchr(h + sizeof(mapped) - 1, '.'))
+	    h += sizeof(mapped) - 1;
+
+	snprintf(buf, addr_size, std::strchr(h, ':') ? "[%s]:%s" : "%s:%s",
+		 h, serv);
+    }
+
+    void accept_client(int const listener, bool const text,
+		       uint64_t const now)
+    {
+	sockaddr_storage addr;
+	socklen_t len = sizeof(addr);
+	int const s = accept(listener, reinterpret_cast<sockaddr*>(&addr), &len);
+
+	if (s != -1) {
+	    char peer[addr_size];
+
+	    print_addr(peer, reinterpret_cast<sockaddr*>(&addr), len);
+	    if (!make_room(text, now)) {
+		syslog(LOG_INFO, "too many clients ... refusing text "
+		       "client %s", peer);
+		send_bye(s);
+		close(s);
+		return;
+	    }
 
 	    int const val = 1;
 
@@ -193,37 +839,107 @@ class State {
 	    else if (-1 == setsockopt(s, SOL_SOCKET, SO_KEEPALIVE,
 				      &val, sizeof(val)))
 		syslog(LOG_WARNING, "couldn't enable KEEPALIVE");
+	    else if (-1 == setsockopt(s, IPPROTO_TCP, TCP_KEEPIDLE,
+				      &tcp_keep_idle, sizeof(tcp_keep_idle)) ||
+		     -1 == setsockopt(s, IPPROTO_TCP, TCP_KEEPINTVL,
+				      &tcp_keep_interval,
+				      sizeof(tcp_keep_interval)) ||
+		     -1 == setsockopt(s, IPPROTO_TCP, TCP_KEEPCNT,
+				      &tcp_keep_count, sizeof(tcp_keep_count)))
+		syslog(LOG_WARNING, "couldn't set KEEPALIVE timing ... "
+		       "using system defaults");
+
+	    Client& c = client[n_clients++];
+
+	    c.fd = s;
+	    c.text = text;
+	    c.line_len = 0;
+	    std::memcpy(c.addr, peer, sizeof(c.addr));
+	    set_client(true);
+
+	    syslog(LOG_INFO, "new %sclient: %s", text ? "text " : "", c.addr);
+	    record_audit(now, true, c.addr);
+
+	    if (send_greeting(n_clients - 1, now))
+		replay_history(n_clients - 1, now);
+	}
+    }
 
-	    s_client = s;
-	    send_state();
+    // Answers pending UDP queries with the current state. The contents
+    // of a query are ignored, but it has to be at least as long as
+    // the report sent back.
 
-	    char buf[22];
+    void check_for_queries()
+    {
+	uint8_t query[32];
+	sockaddr_storage addr;
+	socklen_t len = sizeof(addr);
+
+	for (size_t ii = 0; ii < max_queries; ++ii) {
+	    ssize_t const n = recvfrom(s_query, query, sizeof(query), 0,
+				       reinterpret_cast<sockaddr*>(&addr),
+				       &len);
+
+	    if (n == -1)
+		break;
+	    if (last_stamp != 0 && size_t(n) >= report_size) {
+		uint8_t buf[report_size];
+
+		encode_report(buf, last_stamp, last_value);
+		if (sendto(s_query, buf, sizeof(buf), 0,
+			   reinterpret_cast<sockaddr*>(&addr), len) == -1)
+		    syslog(LOG_WARNING, "couldn't answer query -- %m");
+	    }
+	    len = sizeof(addr);
+	}
+    }
 
-	    print_addr(buf, ntohl(addr.sin_addr.s_addr), ntohs(addr.sin_port));
-	    syslog(LOG_INFO, "new client: %s", buf);
-	} else if (s_client != -1) {
-	    pollfd fds;
+    void check_for_clients(uint64_t const now)
+    {
+	accept_client(s_listen, false, now);
+	accept_client(s_text_listen, true, now);
 
-	    fds.fd = s_client;
-	    fds.events = POLLIN;
+	if (n_clients > 0) {
+	    pollfd fds[max_clients];
 
-	    if (poll(&fds, 1, 0) > 0) {
-		char buffer[32];
+	    for (size_t ii = 0; ii < n_clients; ++ii) {
+		fds[ii].fd = client[ii].fd;
+		fds[ii].events = POLLIN;
+	    }
 
-		if (recv(s_client, buffer, sizeof(buffer),
-			 MSG_PEEK | MSG_DONTWAIT) == 0) {
-		    close(s_client);
-		    s_client = -1;
-		    set_client(false);
-		}
+	    if (poll(fds, n_clients, 0) > 0) {
+		size_t ii = n_clients;
+
+		while (ii-- > 0)
+		    if (fds[ii].revents) {
+			char buffer[32];
+			ssize_t const n = recv(fds[ii].fd, buffer,
+					       sizeof(buffer), MSG_DONTWAIT);
+
+			if (n > 0)
+			    handle_commands(ii, buffer, n, now);
+			else if (n == 0 ||
+				 (errno != EAGAIN && errno != EINTR))
+			    drop_client(ii, now);
+		    }
 	    }
 	}
     }
 
  public:
-    State() :
-	last_stamp(0), last_value(false), s_listen(create_listener()),
-	s_client(-1), h_gpio(open_gpio())
+    State(uint64_t const settle_on, uint64_t const settle_off) :
+	started(get_time()), last_stamp(0), last_value(false),
+	next_keepalive(0), ran_too_long(false), stuck(false),
+	idle_too_long(false), cycles(0), seen_edge(false),
+	longest_run(0), total_runtime(0), shortest_idle(UINT64_MAX),
+	flap_start(0), flap_count(0), flapping(false), history_len(0),
+	history_next(0), input(settle_on, settle_off, min_runtime),
+	s_listen(create_listener(service_port)),
+	s_text_listen(create_listener(text_port)),
+	s_query(create_query_socket(query_port)), n_clients(0),
+	next_test_alarm(0),
+	audit_len(0), audit_next(0),
+	h_gpio(open_gpio())
     {
 	set_client(false);
 	set_activity(false);
@@ -233,29 +949,135 @@ class State {
     {
 	set_client(false);
 	set_activity(false);
-	if (s_client != -1)
-	    close(s_client);
+	while (n_clients > 0)
+	    close(client[--n_clients].fd);
+	close(s_query);
+	close(s_text_listen);
 	close(s_listen);
 	close(h_gpio);
     }
 
     char const* pump_state() const { return last_value ? "on" : "off"; }
 
+    void log_stats() const
+    {
+	syslog(LOG_INFO, "up %llu seconds",
+	       static_cast<unsigned long long>((get_time() - started) / 1000));
+	syslog(LOG_INFO, "pump is %s%s, %u cycles since startup", pump_state(),
+	       stuck ? " (STUCK)" : "", cycles);
+	syslog(LOG_INFO, "%u phantom cycles shorter than %llu ms",
+	       input.phantom_count(),
+	       static_cast<unsigned long long>(min_runtime));
+	unsigned long long const minutes = total_runtime / (60 * 1000);
+
+	syslog(LOG_INFO, "total runtime: %lluh %llum", minutes / 60,
+	       minutes % 60);
+	if (longest_run)
+	    syslog(LOG_INFO, "longest run: %llu seconds",
+		   static_cast<unsigned long long>(longest_run / 1000));
+	if (shortest_idle != UINT64_MAX)
+	    syslog(LOG_INFO, "shortest idle: %llu seconds",
+		   static_cast<unsigned long long>(shortest_idle / 1000));
+	for (size_t ii = 0; ii < audit_len; ++ii) {
+	    Audit const& e = audit_entry(ii);
+
+	    syslog(LOG_INFO, "@ts: %llu, client %s: %s",
+		   static_cast<unsigned long long>(e.stamp),
+		   e.connected ? "connected" : "disconnected", e.addr);
+	}
+    }
+
+    void reset_stats()
+    {
+	cycles = 0;
+	input.clear_phantoms();
+	longest_run = 0;
+	total_runtime = 0;
+	shortest_idle = UINT64_MAX;
+	syslog(LOG_INFO, "statistics cleared");
+    }
+
     void update(uint64_t const stamp)
     {
 	set_activity(true);
-	bool const current = read_pin();
+	bool const current = input.update(read_pin(), stamp);
+	bool const resumed = end_flap_window(stamp);
 
 	if (last_value != current || !last_stamp) {
-	    last_stamp = stamp;
+
+	    // Report the time the new level was first seen rather than
+	    // when it finished settling.
+
+	    uint64_t const when = input.since();
+
+	    if (ran_too_long) {
+		syslog(LOG_NOTICE, "pump turned off after running %llu seconds",
+		       static_cast<unsigned long long>(when - last_stamp) /
+		       1000);
+		ran_too_long = false;
+	    }
+	    if (stuck) {
+		syslog(LOG_NOTICE, "pump is no longer stuck");
+		stuck = false;
+	    }
+	    if (idle_too_long) {
+		syslog(LOG_NOTICE, "pump is running again");
+		idle_too_long = false;
+	    }
+	    if (current && last_stamp && cycles < UINT32_MAX)
+		++cycles;
+	    if (seen_edge) {
+		uint64_t const interval = when - last_stamp;
+
+		if (current)
+		    shortest_idle = std::min(shortest_idle, interval);
+		else {
+		    longest_run = std::max(longest_run, interval);
+		    total_runtime = interval > UINT64_MAX - total_runtime ?
+			UINT64_MAX : total_runtime + interval;
+		}
+	    }
+	    seen_edge = last_stamp != 0;
+	    last_stamp = when;
 	    last_value = current;
+	    record_event(when, current);
 #if 0
-	    syslog(LOG_INFO, "state: %s, @ts: %llu", pump_state(), stamp);
+	    syslog(LOG_INFO, "state: %s, @ts: %llu", pump_state(), when);
 #endif
-	    send_state();
+	    if (++flap_count > flap_limit && !flapping) {
+		syslog(LOG_WARNING, "pump changed state more than %u times "
+		       "in %llu seconds ... holding reports", flap_limit,
+		       static_cast<unsigned long long>(flap_window / 1000));
+		flapping = true;
+	    }
+	    if (!flapping)
+		send_state(stamp);
+	} else {
+	    if (current && !ran_too_long && stamp - last_stamp > max_runtime) {
+		syslog(LOG_WARNING, "pump has been running for more than "
+		       "%llu seconds",
+		       static_cast<unsigned long long>(max_runtime / 1000));
+		ran_too_long = true;
+	    }
+	    if (current && !stuck && stamp - last_stamp > stuck_runtime) {
+		syslog(LOG_ERR, "pump has been running for more than "
+		       "%llu seconds ... it may be stuck",
+		       static_cast<unsigned long long>(stuck_runtime / 1000));
+		stuck = true;
+	    }
+	    if (dry_interval && !current && !idle_too_long &&
+		stamp - last_stamp > dry_interval) {
+		syslog(LOG_NOTICE, "pump hasn't run in %llu hours",
+		       static_cast<unsigned long long>(dry_interval) /
+		       (60 * 60 * 1000));
+		idle_too_long = true;
+	    }
+	    if (resumed || stamp >= next_keepalive)
+		send_state(stamp);
 	}
 
-	check_for_clients();
+	check_for_clients(stamp);
+	check_for_queries();
 	if (!current) {
 	    sleep_until(stamp + 20);
 	    set_activity(false);
@@ -299,19 +1121,31 @@ int main(int, char**)
 
     signal(SIGINT, quit);
     signal(SIGTERM, quit);
+    signal(SIGUSR1, request_stats);
+    signal(SIGUSR2, request_clear);
 
     // Now we're in the main guts of the process.
 
     try {
-	State state;
+	State state(default_debounce_on, default_debounce_off);
 	uint64_t timebase = get_time();
 
-	syslog(LOG_INFO, "initial time: %llu", timebase);
+	syslog(LOG_INFO, "initial time: %llu",
+	       static_cast<unsigned long long>(timebase));
 
 	while (!done) {
 	    timebase += 50;
 	    sleep_until(timebase);
 	    state.update(timebase);
+
+	    if (report_stats) {
+		report_stats = 0;
+		state.log_stats();
+	    }
+	    if (clear_stats) {
+		clear_stats = 0;
+		state.reset_stats();
+	    }
 	}
 
 	syslog(LOG_INFO, "terminating");
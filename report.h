@@ -0,0 +1,29 @@
+#ifndef SUMP_REPORT_H
+#define SUMP_REPORT_H
+
+#include <cstddef>
+#include <cstdint>
+
+// Fills in a binary report: the timestamp in bytes 0-7 followed by
+// the pump state as a 32-bit value, all big-endian. This is kept
+// apart from the socket code so it can be tested on any host.
+
+static size_t const report_size = 12;
+
+inline void encode_report(uint8_t buf[report_size], uint64_t const stamp,
+			  bool const value)
+{
+    buf[0] = stamp >> 56;
+    buf[1] = stamp >> 48;
+    buf[2] = stamp >> 40;
+    buf[3] = stamp >> 32;
+    buf[4] = stamp >> 24;
+    buf[5] = stamp >> 16;
+    buf[6] = stamp >> 8;
+    buf[7] = stamp;
+
+    buf[8] = buf[9] = buf[10] = 0;
+    buf[11] = value;
+}
+
+#endif
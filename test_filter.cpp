@@ -0,0 +1,156 @@
+// Checks the debounce filter against bursts of edges and short runs.
+// Build and run it with `make test`; it doesn't need GPIO, so any host
+// will do.
+
+#include <cstdio>
+#include "filter.h"
+
+static int failures = 0;
+
+// The filter under test along with what it has reported so far.
+
+struct Probe {
+    Filter filter;
+    uint64_t stamp;
+    bool value;
+    unsigned transitions;
+
+    Probe(uint64_t const on, uint64_t const off, uint64_t const run,
+	  bool const initial) :
+	filter(on, off, run), stamp(0),
+	value(filter.update(initial, 0)), transitions(0)
+    {
+    }
+
+    // Holds the pin at `raw` for `ms` milliseconds, sampling every
+    // 10 ms, and counts the changes the filter reports.
+
+    void hold(bool const raw, uint64_t const ms)
+    {
+	for (uint64_t const end = stamp + ms; stamp < end;) {
+	    stamp += 10;
+
+	    bool const v = filter.update(raw, stamp);
+
+	    if (v != value) {
+		value = v;
+		++transitions;
+	    }
+	}
+    }
+};
+
+static void check(char const* name, bool const ok)
+{
+    if (!ok) {
+	std::printf("FAIL %s\n", name);
+	++failures;
+    }
+}
+
+int main()
+{
+    // The first sample is reported as-is.
+
+    {
+	Filter f(100, 100, 0);
+
+	check("first sample on", f.update(true, 1000));
+	check("first sample since", f.since() == 1000);
+    }
+
+    // A burst of edges on the ON side collapses to one transition,
+    // stamped with the last rising edge.
+
+    {
+	Probe p(100, 100, 0, false);
+
+	for (int ii = 0; ii < 5; ++ii) {
+	    p.hold(true, 20);
+	    p.hold(false, 20);
+	}
+	check("on burst held off", !p.value && p.transitions == 0);
+
+	uint64_t const edge = p.stamp + 10;
+
+	p.hold(true, 500);
+	check("on burst one transition", p.value && p.transitions == 1);
+	check("on burst since", p.filter.since() == edge);
+	check("on burst no phantoms", p.filter.phantom_count() == 0);
+    }
+
+    // The same on the OFF side.
+
+    {
+	Probe p(100, 100, 0, true);
+
+	for (int ii = 0; ii < 5; ++ii) {
+	    p.hold(false, 20);
+	    p.hold(true, 20);
+	}
+	check("off burst held on", p.value && p.transitions == 0);
+
+	uint64_t const edge = p.stamp + 10;
+
+	p.hold(false, 500);
+	check("off burst one transition", !p.value && p.transitions == 1);
+	check("off burst since", p.filter.since() == edge);
+    }
+
+    // Each edge uses its own period.
+
+    {
+	Probe p(50, 300, 0, false);
+
+	p.hold(true, 50);
+	check("on before period", !p.value);
+	p.hold(true, 10);
+	check("on after period", p.value);
+	p.hold(false, 300);
+	check("off before period", p.value);
+	p.hold(false, 10);
+	check("off after period", !p.value && p.transitions == 2);
+    }
+
+    // An ON level that gets past the debounce period but not the
+    // minimum run is a phantom cycle and isn't reported.
+
+    {
+	Probe p(100, 100, 2000, false);
+
+	p.hold(true, 500);
+	p.hold(false, 500);
+	check("phantom not reported", !p.value && p.transitions == 0);
+	check("phantom counted", p.filter.phantom_count() == 1);
+
+	p.filter.clear_phantoms();
+	check("phantoms cleared", p.filter.phantom_count() == 0);
+    }
+
+    // A glitch shorter than the debounce period isn't a phantom.
+
+    {
+	Probe p(100, 100, 2000, false);
+
+	p.hold(true, 50);
+	p.hold(false, 500);
+	check("glitch not a phantom", p.filter.phantom_count() == 0);
+    }
+
+    // A run that lasts the minimum is reported, and ending it isn't a
+    // phantom.
+
+    {
+	Probe p(100, 100, 2000, false);
+
+	p.hold(true, 2500);
+	p.hold(false, 500);
+	check("real run reported", !p.value && p.transitions == 2);
+	check("real run not a phantom", p.filter.phantom_count() == 0);
+    }
+
+    if (failures)
+	return 1;
+    std::printf("filter: ok\n");
+    return 0;
+}
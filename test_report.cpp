@@ -0,0 +1,63 @@
+// Checks the exact bytes of the binary report. Build and run it with
+// `make test`; it doesn't need GPIO or sockets, so any host will do.
+
+#include <cstdio>
+#include <cstring>
+#include "report.h"
+
+static int failures = 0;
+
+static void check(char const* name, uint64_t const stamp, bool const value,
+		  uint8_t const (&expected)[report_size])
+{
+    uint8_t buf[report_size];
+
+    // Fill the buffer first so bytes the encoder forgets to set show
+    // up as failures.
+
+    std::memset(buf, 0xaa, sizeof(buf));
+    encode_report(buf, stamp, value);
+
+    if (std::memcmp(buf, expected, sizeof(buf)) != 0) {
+	std::printf("FAIL %s:", name);
+	for (size_t ii = 0; ii < sizeof(buf); ++ii)
+	    std::printf(" %02x", buf[ii]);
+	std::printf("\n");
+	++failures;
+    }
+}
+
+int main()
+{
+    static uint8_t const zero_off[report_size] = {
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x00
+    };
+    static uint8_t const ordered_on[report_size] = {
+	0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+	0x00, 0x00, 0x00, 0x01
+    };
+    static uint8_t const ordered_off[report_size] = {
+	0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+	0x00, 0x00, 0x00, 0x00
+    };
+    static uint8_t const typical_on[report_size] = {
+	0x00, 0x00, 0x00, 0x00, 0x07, 0x5b, 0xcd, 0x15,
+	0x00, 0x00, 0x00, 0x01
+    };
+    static uint8_t const max_on[report_size] = {
+	0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+	0x00, 0x00, 0x00, 0x01
+    };
+
+    check("zero stamp, off", 0, false, zero_off);
+    check("byte order, on", 0x0102030405060708ULL, true, ordered_on);
+    check("byte order, off", 0x0102030405060708ULL, false, ordered_off);
+    check("typical stamp, on", 123456789, true, typical_on);
+    check("maximum stamp, on", UINT64_MAX, true, max_on);
+
+    if (failures)
+	return 1;
+    std::printf("report layout: ok\n");
+    return 0;
+}